@@ -18,6 +18,16 @@
 //! * 任意オプションでvagrantコマンドを実行する。(pluginのコマンド等の非標準コマンドの実行に使用)
 //!     * `vm NAME -c vbguest`
 //!     * `vm NAME -c 'vbguest --do rebuild'`
+//! * 設定ファイルの`[alias]`に登録した名前で、よく使う呼び出しをショートカットする
+//!     * `vm provision` (`[alias] provision = "myvm -c 'up --provision'"` のように定義)
+//! * カレントディレクトリから上に向かって`.vm.toml`を探し、見つかった分だけ
+//!   グローバルな設定の上に重ねて使う(プロジェクトローカルな`vm_list`の追加)
+//! * 環境変数でconfigの値を上書きする
+//!     * `VM_VAGRANT_PATH`, `VM_VM_<NAME>_PATH` (優先順位は env > プロジェクトローカル > グローバル)
+//! * vm_listの各エントリごとにvagrant以外のバックエンド(multipass, libvirt等)を選ぶ
+//!     * `vm_list.myvm.backend = "multipass"` のように設定し、`Provisioner`実装を登録しておく
+//! * 設定ファイルの`[tasks]`に登録した一連の呼び出しを、順番に実行する
+//!     * `vm NAME run TASK` (失敗したステップがあればそこで打ち切る)
 //!
 //! ## 将来的に必要性を感じたら作る
 //! * ある名前のvmに対してvagrantコマンドのサブコマンドを実行
@@ -38,8 +48,8 @@ extern crate serde_derive;
 extern crate toml;
 
 use failure::Error;
-use std::collections::BTreeMap;
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
@@ -50,6 +60,10 @@ use std::process::{Command, ExitStatus};
 pub struct Info {
     name: String,
     path: PathBuf,
+    /// このVMを操作する際に使うバックエンドの名前(`Provisioner`レジストリのキー)。
+    /// 省略された場合はデフォルトの`"vagrant"`が使われる。
+    #[serde(default)]
+    backend: Option<String>,
 }
 
 impl Info {
@@ -60,22 +74,54 @@ impl Info {
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
+
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_ref().map(String::as_str)
+    }
+}
+
+/// プロジェクトローカルなconfigファイルの名前。カレントディレクトリから
+/// ルートに向かって探索し、見つかったものをグローバルなconfigの上に重ねる。
+const LOCAL_CONFIG_FILE_NAME: &str = ".vm.toml";
+
+/// `vagrant_path` を上書きする環境変数名。
+const VAGRANT_PATH_ENV_VAR: &str = "VM_VAGRANT_PATH";
+
+/// `vm_list` の各エントリの`path`を上書きする環境変数名のprefix/suffix。
+/// 実際の変数名は `VM_VM_<NAME>_PATH` (`<NAME>`はvm名を大文字化し`-`を`_`にしたもの)。
+const VM_PATH_ENV_PREFIX: &str = "VM_VM_";
+const VM_PATH_ENV_SUFFIX: &str = "_PATH";
+
+fn empty_path() -> PathBuf {
+    PathBuf::new()
 }
 
 /// configファイルの情報
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default = "empty_path")]
     vagrant_path: PathBuf,
+    #[serde(default)]
     vm_list: BTreeMap<String, Info>,
+    /// `vm NAME` の代わりに使える、任意のコマンド文字列へのショートカット。
+    #[serde(default)]
+    alias: BTreeMap<String, String>,
+    /// `vm NAME run TASK` で実行される、名前付きの一連のバックエンド呼び出し。
+    /// 各要素はバックエンドのサブコマンドと引数(例: `"up"`, `"ssh -c 'systemctl restart app'"`)、
+    /// または`vm NAME -c OPTIONS`と同じ`-c OPTIONS`形式の生渡し(例: `"-c 'vbguest --do rebuild'"`)。
+    #[serde(default)]
+    tasks: BTreeMap<String, Vec<String>>,
 }
 
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
         if path.as_ref().exists() {
+            let display_path = path.as_ref().to_path_buf();
             let file = File::open(path)?;
             let mut buf = Vec::new();
             let _ = BufReader::new(file).read_to_end(&mut buf)?;
-            Ok(toml::from_slice(&buf)?)
+            toml::from_slice(&buf)
+                .map_err(|err| ConfigParseError::new(&display_path, &buf, &err).into())
         } else {
             let vagrant_path = if cfg!(windows) {
                 "vagrant.exe"
@@ -85,10 +131,124 @@ impl Config {
             Ok(Config {
                 vagrant_path: PathBuf::from(vagrant_path),
                 vm_list: BTreeMap::new(),
+                alias: BTreeMap::new(),
+                tasks: BTreeMap::new(),
             })
         }
     }
 
+    /// `global_path` のconfigを土台にして、`start` からファイルシステムの
+    /// ルートに向かって歩きながら見つかった `.vm.toml` を順に重ねていく。
+    /// `start` に近い層ほど優先される。
+    ///
+    /// これにより、リポジトリごとに `vm_list` のエントリを足したり
+    /// `vagrant_path` を上書きしたりを、ユーザーのグローバルな設定を
+    /// 汚さずに行える。
+    pub fn load_layered<P1: AsRef<Path>, P2: AsRef<Path>>(
+        global_path: P1,
+        start: P2,
+    ) -> Result<Config, Error> {
+        let mut config = Config::from_file(global_path)?;
+
+        let mut local_paths = Config::discover_local_config_paths(start);
+        // `discover_local_config_paths` は近い順に積むので、遠い方から
+        // 順番に重ねていくことで「近い層ほど優先」を実現する。
+        local_paths.reverse();
+
+        for path in local_paths {
+            let layer = Config::from_file(&path)?;
+            config = config.merged_over(layer);
+        }
+
+        Ok(config)
+    }
+
+    fn discover_local_config_paths<P: AsRef<Path>>(start: P) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let mut dir = Some(start.as_ref().to_path_buf());
+
+        while let Some(current) = dir {
+            let candidate = current.join(LOCAL_CONFIG_FILE_NAME);
+            if candidate.is_file() {
+                paths.push(candidate);
+            }
+            dir = current.parent().map(|p| p.to_path_buf());
+        }
+
+        paths
+    }
+
+    /// `self` を土台にして `other` を重ねた `Config` を返す。
+    /// `vm_list`・`alias` は同名キーを `other` 側で上書きするマージ、
+    /// `vagrant_path` は `other` が空でなければそちらを採用する。
+    fn merged_over(mut self, other: Config) -> Config {
+        if !other.vagrant_path.as_os_str().is_empty() {
+            self.vagrant_path = other.vagrant_path;
+        }
+
+        for (name, info) in other.vm_list {
+            self.vm_list.insert(name, info);
+        }
+
+        for (name, command) in other.alias {
+            self.alias.insert(name, command);
+        }
+
+        for (name, steps) in other.tasks {
+            self.tasks.insert(name, steps);
+        }
+
+        self
+    }
+
+    /// 環境変数でconfigの値を上書きする。優先順位は
+    /// 環境変数 > プロジェクトローカルのレイヤー(`.vm.toml`) > グローバルなconfigファイル。
+    ///
+    /// * `VM_VAGRANT_PATH` ... `vagrant_path` を上書きする
+    /// * `VM_VM_<NAME>_PATH` ... `<NAME>`を大文字化し`-`を`_`にした名前に対応する
+    ///   `vm_list`エントリの`path`を上書きする。該当エントリが無ければ新規に追加する
+    pub fn apply_env_overrides(&mut self) {
+        if let Ok(vagrant_path) = std::env::var(VAGRANT_PATH_ENV_VAR) {
+            if !vagrant_path.is_empty() {
+                self.vagrant_path = PathBuf::from(vagrant_path);
+            }
+        }
+
+        // 既存エントリが消費した環境変数名を覚えておく。`vm_name_from_path_env_var`は
+        // `-`/`_`を区別できずに潰すため、例えば既存の`web_vm`を`VM_VM_WEB_VM_PATH`で
+        // 上書きした後、同じ変数を逆変換すると`web-vm`という別名に見えてしまい、
+        // 同じパスを指す重複エントリを注入してしまう。ここで消費済みとして覚えておき、
+        // 新規エントリの注入対象から除外する。
+        let mut consumed_vars = HashSet::new();
+
+        for (name, info) in self.vm_list.iter_mut() {
+            let var_name = vm_path_env_var_name(name);
+            if let Ok(path) = std::env::var(&var_name) {
+                info.path = PathBuf::from(path);
+            }
+            consumed_vars.insert(var_name);
+        }
+
+        for (key, value) in std::env::vars() {
+            if consumed_vars.contains(&key) {
+                continue;
+            }
+
+            if let Some(name) = vm_name_from_path_env_var(&key) {
+                if !self.vm_list.contains_key(&name) {
+                    self.vm_list.insert(
+                        name.clone(),
+                        Info {
+                            name,
+                            path: PathBuf::from(value),
+                            backend: None,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
     pub fn vagrant_path(&self) -> &Path {
         self.vagrant_path.as_path()
     }
@@ -97,20 +257,97 @@ impl Config {
         &self.vm_list
     }
 
+    pub fn alias(&self) -> &BTreeMap<String, String> {
+        &self.alias
+    }
+
+    pub fn tasks(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.tasks
+    }
+
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
         BufWriter::new(File::create(path)?).write_all(toml::to_string_pretty(self)?.as_bytes())?;
         Ok(())
     }
 }
 
-pub trait RunVagrant {
-    fn subcommand<S: AsRef<OsStr>, T: AsRef<OsStr>>(
-        &self,
-        command: S,
-        options: &[T],
-    ) -> Result<ExitStatus, Error>;
+/// vm名から対応する`VM_VM_<NAME>_PATH`形式の環境変数名を組み立てる。
+fn vm_path_env_var_name(name: &str) -> String {
+    format!(
+        "{}{}{}",
+        VM_PATH_ENV_PREFIX,
+        name.to_uppercase().replace('-', "_"),
+        VM_PATH_ENV_SUFFIX
+    )
+}
 
-    fn raw<S: AsRef<OsStr>>(&self, options: &[S]) -> Result<ExitStatus, Error>;
+/// `VM_VM_<NAME>_PATH`形式の環境変数名からvm名を取り出す。
+/// その形をしていない変数名には`None`を返す。
+fn vm_name_from_path_env_var(key: &str) -> Option<String> {
+    if !key.starts_with(VM_PATH_ENV_PREFIX) || !key.ends_with(VM_PATH_ENV_SUFFIX) {
+        return None;
+    }
+
+    let name = &key[VM_PATH_ENV_PREFIX.len()..key.len() - VM_PATH_ENV_SUFFIX.len()];
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_lowercase().replace('_', "-"))
+    }
+}
+
+/// シェルの単語分割に近い形で文字列をトークンに分割する。
+/// シングルクォート・ダブルクォートで囲まれた範囲は空白で分割しない
+/// (例: `"up --provision"` は1トークンとして扱われる)。
+pub fn tokenize_quoted<S: AsRef<str>>(input: S) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote = None;
+
+    for c in input.as_ref().chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                in_token = true;
+            }
+            None if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::replace(&mut current, String::new()));
+                    in_token = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if in_token {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+/// デフォルトのバックエンド名。`Info::backend`が省略された場合に使われる。
+const DEFAULT_BACKEND_NAME: &str = "vagrant";
+
+/// vagrant以外のツール(multipass, libvirt, docker-composeなど)もvm_listの
+/// エントリごとに選べるようにするための抽象化。`up`/`halt`/`ssh`のような
+/// 汎用的な動詞を、各バックエンドの実際のコマンドライン呼び出しに変換する。
+pub trait Provisioner {
+    /// 汎用的な動詞とオプションを、このバックエンドの実際の引数列に変換する
+    /// (実行ファイル自体は含まない)。
+    fn translate(&self, command: &str, options: &[String]) -> Vec<OsString>;
+
+    fn subcommand(&self, command: &str, options: &[String]) -> Result<ExitStatus, Error>;
+
+    /// Pass options straight through to the backend, bypassing verb translation.
+    fn raw(&self, options: &[String]) -> Result<ExitStatus, Error>;
 }
 
 pub struct Vagrant {
@@ -123,42 +360,148 @@ impl Vagrant {
     }
 }
 
-impl RunVagrant for Vagrant {
-    fn subcommand<S: AsRef<OsStr>, T: AsRef<OsStr>>(
-        &self,
-        command: S,
-        options: &[T],
-    ) -> Result<ExitStatus, Error> {
+impl Provisioner for Vagrant {
+    fn translate(&self, command: &str, options: &[String]) -> Vec<OsString> {
+        let mut args = vec![OsString::from(command)];
+        args.extend(options.iter().map(OsString::from));
+        args
+    }
+
+    fn subcommand(&self, command: &str, options: &[String]) -> Result<ExitStatus, Error> {
         Ok(Command::new(&self.path)
-            .arg(command.as_ref())
-            .args(options)
+            .args(self.translate(command, options))
             .status()?)
     }
 
     /// Pass vagrant command options.
-    fn raw<S: AsRef<OsStr>>(&self, options: &[S]) -> Result<ExitStatus, Error> {
+    fn raw(&self, options: &[String]) -> Result<ExitStatus, Error> {
         Ok(Command::new(&self.path).args(options).status()?)
     }
 }
 
+/// configファイルのパース失敗を、どのファイルの何行目で何が起きたかが
+/// わかる形に包んだエラー。`toml::de::Error::line_col`で行/列を、
+/// 元のメッセージから`key \`...\``の形の記述を抜き出してキーのパスを添える。
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "{}:{}:{}: {}", path, line, column, message)]
+pub struct ConfigParseError {
+    path: String,
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl ConfigParseError {
+    fn new(path: &Path, source: &[u8], err: &toml::de::Error) -> ConfigParseError {
+        let (line, column) = err
+            .line_col()
+            .map(|(line, column)| (line + 1, column + 1))
+            .unwrap_or((0, 0));
+
+        let excerpt = String::from_utf8_lossy(source)
+            .lines()
+            .nth(line.saturating_sub(1))
+            .map(str::trim)
+            .unwrap_or("")
+            .to_string();
+
+        let message = match (offending_key(&err.to_string()), excerpt.is_empty()) {
+            (Some(key), false) => format!("invalid value for {} (`{}`)", key, excerpt),
+            (Some(key), true) => format!("invalid value for {}", key),
+            (None, _) => err.to_string(),
+        };
+
+        ConfigParseError {
+            path: path.to_string_lossy().into_owned(),
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+/// tomlのエラーメッセージ末尾によく現れる `` for key `a.b.c` `` から
+/// キーのパス部分だけを抜き出す。見つからなければ`None`。
+fn offending_key(message: &str) -> Option<&str> {
+    let start = message.find("key `")? + "key `".len();
+    let rest = &message[start..];
+    let end = rest.find('`')?;
+    Some(&rest[..end])
+}
+
 #[derive(Debug, Clone, Fail)]
 #[fail(display = "cannot find {} from vm list in config file", name)]
 pub struct VmInfoFindError {
     name: String,
 }
 
-pub struct Vm<V: RunVagrant> {
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "unknown provisioner backend: {}", name)]
+pub struct BackendNotFoundError {
+    name: String,
+}
+
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "cannot find task \"{}\" in config file", name)]
+pub struct TaskNotFoundError {
+    name: String,
+}
+
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "task \"{}\" has no steps to run", name)]
+pub struct EmptyTaskError {
+    name: String,
+}
+
+/// バックエンド名から`Provisioner`を引くレジストリ。デフォルトでは
+/// `"vagrant"`という名前で`Vagrant`バックエンドが登録されている。
+pub struct BackendRegistry {
+    backends: BTreeMap<String, Box<dyn Provisioner>>,
+}
+
+impl BackendRegistry {
+    pub fn new<P: AsRef<Path>>(vagrant_path: P) -> BackendRegistry {
+        let mut backends: BTreeMap<String, Box<dyn Provisioner>> = BTreeMap::new();
+        backends.insert(
+            DEFAULT_BACKEND_NAME.to_string(),
+            Box::new(Vagrant::new(vagrant_path)),
+        );
+        BackendRegistry { backends }
+    }
+
+    /// サードパーティ製のバックエンドを名前付きで登録する。
+    pub fn register<S: Into<String>>(&mut self, name: S, backend: Box<dyn Provisioner>) {
+        self.backends.insert(name.into(), backend);
+    }
+
+    /// `name`に対応する`Provisioner`を解決する。`None`ならデフォルトの
+    /// `"vagrant"`バックエンドを返す。
+    pub fn resolve(&self, name: Option<&str>) -> Result<&dyn Provisioner, Error> {
+        let name = name.unwrap_or(DEFAULT_BACKEND_NAME);
+        self.backends
+            .get(name)
+            .map(|backend| backend.as_ref())
+            .ok_or_else(|| {
+                BackendNotFoundError {
+                    name: name.to_string(),
+                }.into()
+            })
+    }
+}
+
+pub struct Vm {
     config_file_path: PathBuf,
     config: Config,
-    vagrant: V,
+    backends: BackendRegistry,
 }
 
-impl<V: RunVagrant> Vm<V> {
-    pub fn new<P: AsRef<Path>>(path: P, config: Config, vagrant: V) -> Result<Vm<V>, Error> {
+impl Vm {
+    pub fn new<P: AsRef<Path>>(path: P, config: Config) -> Result<Vm, Error> {
+        let backends = BackendRegistry::new(config.vagrant_path());
         Ok(Vm {
             config_file_path: path.as_ref().to_path_buf(),
             config,
-            vagrant,
+            backends,
         })
     }
 
@@ -170,6 +513,11 @@ impl<V: RunVagrant> Vm<V> {
         &self.config
     }
 
+    /// サードパーティ製のバックエンドを名前付きで登録する。
+    pub fn register_backend<S: Into<String>>(&mut self, name: S, backend: Box<dyn Provisioner>) {
+        self.backends.register(name, backend);
+    }
+
     pub fn cd<S: AsRef<str>>(&self, name: S) -> Result<(), Error> {
         let info = self
             .config
@@ -191,6 +539,7 @@ impl<V: RunVagrant> Vm<V> {
         let info = Info {
             name: name.as_ref().to_string(),
             path: path.as_ref().to_path_buf(),
+            backend: None,
         };
         self.config.vm_list.insert(info.name.clone(), info)
     }
@@ -204,17 +553,74 @@ impl<V: RunVagrant> Vm<V> {
         Ok(())
     }
 
-    pub fn vagrant<S: AsRef<OsStr>, T: AsRef<OsStr>>(
+    /// エントリが選んだバックエンド(`backend`が`None`ならデフォルトの`vagrant`)
+    /// 経由でサブコマンドを実行する。
+    pub fn vagrant(
         &self,
-        command: S,
-        options: &[T],
+        backend: Option<&str>,
+        command: &str,
+        options: &[String],
     ) -> Result<ExitStatus, Error> {
-        Ok(self.vagrant.subcommand(command, options)?)
+        Ok(self.backends.resolve(backend)?.subcommand(command, options)?)
     }
 
     /// Pass vagrant command options.
-    pub fn vagrant_raw<S: AsRef<OsStr>>(&self, options: &[S]) -> Result<ExitStatus, Error> {
-        Ok(self.vagrant.raw(options)?)
+    pub fn vagrant_raw(&self, backend: Option<&str>, options: &[String]) -> Result<ExitStatus, Error> {
+        Ok(self.backends.resolve(backend)?.raw(options)?)
+    }
+
+    /// `config`の`[tasks]`に登録された一連のステップを、`vm_name`のエントリの
+    /// ディレクトリで順番に実行する。各ステップはバックエンドのサブコマンドと引数、
+    /// または`vm NAME -c OPTIONS`と同じ`-c OPTIONS`形式の生渡しで書く。
+    /// 途中のステップが失敗したら、そこで打ち切ってその`ExitStatus`を返す。
+    pub fn run_task<S: AsRef<str>, T: AsRef<str>>(
+        &self,
+        vm_name: S,
+        task_name: T,
+    ) -> Result<ExitStatus, Error> {
+        let info = self
+            .config
+            .vm_list
+            .get(vm_name.as_ref())
+            .ok_or_else(|| VmInfoFindError {
+                name: vm_name.as_ref().to_string(),
+            })?;
+        let steps = self
+            .config
+            .tasks
+            .get(task_name.as_ref())
+            .ok_or_else(|| TaskNotFoundError {
+                name: task_name.as_ref().to_string(),
+            })?;
+
+        std::env::set_current_dir(info.path())?;
+
+        let mut last_status = None;
+        for step in steps {
+            let tokens = tokenize_quoted(step);
+
+            let step_status = if tokens.first().map(String::as_str) == Some("-c") {
+                let raw_options = tokens
+                    .get(1)
+                    .map(tokenize_quoted)
+                    .unwrap_or_else(Vec::new);
+                self.vagrant_raw(info.backend(), &raw_options)?
+            } else if let Some((command, options)) = tokens.split_first() {
+                self.vagrant(info.backend(), command, options)?
+            } else {
+                continue;
+            };
+
+            let succeeded = step_status.success();
+            last_status = Some(step_status);
+            if !succeeded {
+                break;
+            }
+        }
+
+        Ok(last_status.ok_or_else(|| EmptyTaskError {
+            name: task_name.as_ref().to_string(),
+        })?)
     }
 
     pub fn get_info<S: AsRef<str>>(&self, name: S) -> Option<&Info> {
@@ -278,4 +684,155 @@ vm2 = "/home/user/vm/vm2"
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn tokenize_quoted_splits_on_whitespace() {
+        assert_eq!(tokenize_quoted("up --provision"), vec!["up", "--provision"]);
+    }
+
+    #[test]
+    fn tokenize_quoted_keeps_a_quoted_span_as_one_token() {
+        assert_eq!(
+            tokenize_quoted("ssh -c 'systemctl restart app'"),
+            vec!["ssh", "-c", "systemctl restart app"]
+        );
+    }
+
+    #[test]
+    fn tokenize_quoted_ignores_repeated_whitespace_and_empty_input() {
+        assert_eq!(
+            tokenize_quoted("  up   --provision  "),
+            vec!["up", "--provision"]
+        );
+        assert!(tokenize_quoted("").is_empty());
+        assert!(tokenize_quoted("   ").is_empty());
+    }
+
+    fn info(path: &str) -> Info {
+        Info {
+            name: String::new(),
+            path: PathBuf::from(path),
+            backend: None,
+        }
+    }
+
+    #[test]
+    fn discover_local_config_paths_orders_nearest_first() {
+        let base = std::env::temp_dir().join(format!(
+            "vm_test_discover_local_config_paths_{}",
+            std::process::id()
+        ));
+        let nested = base.join("a").join("b");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(base.join(LOCAL_CONFIG_FILE_NAME), "").unwrap();
+        std::fs::write(base.join("a").join(LOCAL_CONFIG_FILE_NAME), "").unwrap();
+
+        let paths = Config::discover_local_config_paths(&nested);
+
+        std::fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(
+            paths,
+            vec![
+                base.join("a").join(LOCAL_CONFIG_FILE_NAME),
+                base.join(LOCAL_CONFIG_FILE_NAME),
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_over_prefers_other_for_shared_keys_but_keeps_the_rest() {
+        let mut global_vm_list = BTreeMap::new();
+        global_vm_list.insert("shared".to_string(), info("/global/shared"));
+        global_vm_list.insert("only_global".to_string(), info("/global/only"));
+        let global = Config {
+            vagrant_path: PathBuf::from("vagrant"),
+            vm_list: global_vm_list,
+            alias: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+        };
+
+        let mut local_vm_list = BTreeMap::new();
+        local_vm_list.insert("shared".to_string(), info("/local/shared"));
+        let local = Config {
+            vagrant_path: PathBuf::new(),
+            vm_list: local_vm_list,
+            alias: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+        };
+
+        let merged = global.merged_over(local);
+
+        assert_eq!(
+            merged.vm_list.get("shared").unwrap().path,
+            PathBuf::from("/local/shared")
+        );
+        assert_eq!(
+            merged.vm_list.get("only_global").unwrap().path,
+            PathBuf::from("/global/only")
+        );
+        assert_eq!(merged.vagrant_path, PathBuf::from("vagrant"));
+    }
+
+    #[test]
+    fn vm_path_env_var_name_uppercases_and_normalizes_dashes() {
+        assert_eq!(vm_path_env_var_name("web-vm"), "VM_VM_WEB_VM_PATH");
+    }
+
+    #[test]
+    fn vm_name_from_path_env_var_roundtrips_names_without_separators() {
+        let key = vm_path_env_var_name("webvm");
+        assert_eq!(vm_name_from_path_env_var(&key), Some("webvm".to_string()));
+    }
+
+    #[test]
+    fn apply_env_overrides_does_not_duplicate_an_underscored_name() {
+        // `VM_VM_WEB_VM_TEST_PATH`は`web_vm_test`と`web-vm-test`のどちらの
+        // vm名からも同じ変数名になる。既存の`web_vm_test`エントリがこの変数を
+        // 消費した場合、逆変換で別名(`web-vm-test`)と誤認して重複登録しては
+        // いけない。
+        let name = "web_vm_test_apply_env_overrides_dup";
+        let var_name = vm_path_env_var_name(name);
+        std::env::set_var(&var_name, "/from/env");
+
+        let mut vm_list = BTreeMap::new();
+        vm_list.insert(name.to_string(), info("/original"));
+        let mut config = Config {
+            vagrant_path: PathBuf::new(),
+            vm_list,
+            alias: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+        };
+
+        config.apply_env_overrides();
+
+        std::env::remove_var(&var_name);
+
+        assert_eq!(config.vm_list.len(), 1);
+        assert_eq!(
+            config.vm_list.get(name).unwrap().path,
+            PathBuf::from("/from/env")
+        );
+    }
+
+    #[test]
+    fn offending_key_extracts_the_backtick_quoted_key_path() {
+        let message = "invalid type: integer, expected a string for key `vm_list.myvm.path`";
+        assert_eq!(offending_key(message), Some("vm_list.myvm.path"));
+    }
+
+    #[test]
+    fn offending_key_is_none_without_a_key_mention() {
+        assert_eq!(offending_key("unexpected end of input"), None);
+    }
+
+    #[test]
+    fn config_parse_error_reports_the_file_path() {
+        let source = b"vagrant_path = 1\n";
+        let err = toml::from_slice::<Config>(source).unwrap_err();
+        let parse_error = ConfigParseError::new(Path::new("config.toml"), source, &err);
+
+        assert_eq!(parse_error.path, "config.toml");
+        assert!(format!("{}", parse_error).starts_with("config.toml:"));
+    }
 }
@@ -7,15 +7,33 @@ extern crate vm;
 
 use directories::ProjectDirs;
 use failure::Error;
+use std::collections::HashSet;
 use std::ffi::OsString;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+/// `args_parser` が知っているサブコマンド名の一覧。
+/// alias解決時に「エイリアス名なのか、既存のサブコマンド名なのか」を区別するために使う。
+const KNOWN_SUBCOMMANDS: [&str; 5] = [
+    "list",
+    "add",
+    "remove",
+    "backup_config_file",
+    "find_vagrantfiles",
+];
+
+/// aliasからaliasへの展開がこの回数を超えたら無限ループとみなして諦める。
+const MAX_ALIAS_DEPTH: usize = 16;
+
 fn main() -> Result<(), Error> {
-    let status = match Args::new(args_parser(), std::env::args()) {
-        Ok(args) => run(&args)?,
+    let config_file_path = config_file_path();
+    std::fs::create_dir_all(config_file_path.parent().unwrap())?;
+    let config = vm::Config::load_layered(&config_file_path, std::env::current_dir()?)?;
+
+    let status = match Args::new(args_parser(), std::env::args(), &config) {
+        Ok(args) => run(&config_file_path, config, &args)?,
         Err(err) => {
             eprintln!("{}", err);
             2
@@ -25,22 +43,25 @@ fn main() -> Result<(), Error> {
     std::process::exit(status);
 }
 
-fn run(args: &Args) -> Result<i32, Error> {
-    let config_file_path = config_file_path();
-    std::fs::create_dir_all(config_file_path.parent().unwrap())?;
+fn run(config_file_path: &Path, mut config: vm::Config, args: &Args) -> Result<i32, Error> {
+    config.apply_env_overrides();
 
-    let config = vm::Config::from_file(&config_file_path)?;
-    let vagrant = vm::Vagrant::new(config.vagrant_path());
-    let mut vm = vm::Vm::new(&config_file_path, config, vagrant)?;
+    // 初回起動(グローバルなconfigファイルがまだ無い)の場合、ここに保存するのは
+    // `config`(プロジェクトローカルの`.vm.toml`や環境変数が重なった結果)ではなく、
+    // グローバルな層だけから作られたconfigにする。でないと、プロジェクトローカルな
+    // エントリや`VM_VM_*_PATH`由来のエントリが、ユーザーのグローバルなconfigに
+    // そのまま書き込まれてしまう。
+    let config_file_existed = config_file_path.exists();
+    let mut vm = vm::Vm::new(config_file_path, config)?;
 
-    if !config_file_path.exists() {
-        vm.config().save_to_file(config_file_path.as_path())?;
+    if !config_file_existed {
+        vm::Config::from_file(config_file_path)?.save_to_file(config_file_path)?;
     }
 
     Ok(run_vagrant(args, &mut vm)?)
 }
 
-fn run_vagrant<T: vm::RunVagrant>(args: &Args, vm: &mut vm::Vm<T>) -> Result<i32, Error> {
+fn run_vagrant(args: &Args, vm: &mut vm::Vm) -> Result<i32, Error> {
     match &args.subcommand {
         SubCommand::List => vm
             .list()
@@ -97,12 +118,18 @@ fn run_vagrant<T: vm::RunVagrant>(args: &Args, vm: &mut vm::Vm<T>) -> Result<i32
         } => {
             return if let Some(info) = vm.get_info(vm_name) {
                 std::env::set_current_dir(info.path())?;
-                Ok(vm.vagrant_raw(options.as_slice())?.code().unwrap_or(0))
+                Ok(vm.vagrant_raw(info.backend(), options.as_slice())?.code().unwrap_or(0))
             } else {
                 eprintln!("{} is not found in vm_list", vm_name);
                 Ok(1)
             };
         }
+        SubCommand::RunTask {
+            vm_name: vm_name,
+            task_name: task_name,
+        } => {
+            return Ok(vm.run_task(vm_name, task_name)?.code().unwrap_or(0));
+        }
     }
 
     Ok(0)
@@ -119,11 +146,12 @@ struct Args {
 }
 
 impl Args {
-    fn new<I, T>(app: clap::App, args: I) -> Result<Args, Error>
+    fn new<I, T>(app: clap::App, args: I, config: &vm::Config) -> Result<Args, Error>
     where
         I: IntoIterator<Item = T>,
         T: Into<OsString> + Clone,
     {
+        let args = expand_alias(args.into_iter().map(Into::into).collect(), config)?;
         let matches = app.get_matches_from_safe(args)?;
         let subcommand = match matches.subcommand() {
             ("list", Some(sub_matches)) => SubCommand::List,
@@ -149,22 +177,29 @@ impl Args {
                         .ok_or(ArgsError::NoFindVagrantfilesBasePath)?,
                 )),
             },
-            _ => SubCommand::Raw {
-                vm_name: matches
+            _ => {
+                let vm_name = matches
                     .value_of("vm_name")
                     .ok_or(ArgsError::NoVmName)?
-                    .to_string(),
-                options: matches
-                    .value_of("vagrant_options")
-                    .map(|options_str| {
-                        // TODO: 任意パラメータなのでこれだと `'a b'` みたいなのが来るとバグる
-                        options_str
-                            .split_whitespace()
-                            .map(|s| s.to_string())
-                            .collect()
-                    })
-                    .unwrap_or_else(|| Vec::new()),
-            },
+                    .to_string();
+
+                match matches.value_of("task_verb") {
+                    Some("run") => SubCommand::RunTask {
+                        vm_name,
+                        task_name: matches
+                            .value_of("task_name")
+                            .ok_or(ArgsError::NoTaskName)?
+                            .to_string(),
+                    },
+                    _ => SubCommand::Raw {
+                        vm_name,
+                        options: matches
+                            .value_of("vagrant_options")
+                            .map(vm::tokenize_quoted)
+                            .unwrap_or_else(|| Vec::new()),
+                    },
+                }
+            }
         };
 
         Ok(Args { subcommand })
@@ -189,6 +224,10 @@ enum SubCommand {
         vm_name: String,
         options: Vec<String>,
     },
+    RunTask {
+        vm_name: String,
+        task_name: String,
+    },
 }
 
 #[derive(Debug, Clone, Fail)]
@@ -199,6 +238,58 @@ enum ArgsError {
     NoVmPath,
     #[fail(display = "Not specified a find path")]
     NoFindVagrantfilesBasePath,
+    #[fail(display = "Not specified a task name")]
+    NoTaskName,
+    #[fail(display = "alias \"{}\" is referenced recursively", name)]
+    AliasRecursion { name: String },
+    #[fail(
+        display = "alias \"{}\" exceeded the maximum resolution depth ({})",
+        name,
+        MAX_ALIAS_DEPTH
+    )]
+    AliasTooDeep { name: String },
+}
+
+/// argsの先頭(vm_name相当の位置)が `[alias]` に定義されたキーであれば、
+/// その値を再トークン化して展開する。サブコマンド名や既存のvm_listのエントリ名は
+/// aliasより優先されるので、同名があってもaliasとしては解決されない。
+///
+/// cargoの `aliased_command` と同様、alias→aliasの再帰展開も許容するが、
+/// 同じ名前を二度踏んだら無限ループとみなしてエラーにする。
+fn expand_alias(mut args: Vec<OsString>, config: &vm::Config) -> Result<Vec<OsString>, Error> {
+    let mut visited = HashSet::new();
+
+    for depth in 0..=MAX_ALIAS_DEPTH {
+        let name = match args.get(1).and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => return Ok(args),
+        };
+
+        if KNOWN_SUBCOMMANDS.contains(&name.as_str()) || config.vm_list().contains_key(&name) {
+            return Ok(args);
+        }
+
+        let alias_value = match config.alias().get(&name) {
+            Some(value) => value.clone(),
+            None => return Ok(args),
+        };
+
+        if !visited.insert(name.clone()) {
+            return Err(ArgsError::AliasRecursion { name }.into());
+        }
+
+        if depth == MAX_ALIAS_DEPTH {
+            return Err(ArgsError::AliasTooDeep { name }.into());
+        }
+
+        let mut expanded = Vec::with_capacity(args.len());
+        expanded.push(args[0].clone());
+        expanded.extend(vm::tokenize_quoted(&alias_value).into_iter().map(OsString::from));
+        expanded.extend(args.into_iter().skip(2));
+        args = expanded;
+    }
+
+    unreachable!("the last iteration of the alias loop always returns")
 }
 
 fn args_parser<'a, 'b>() -> clap::App<'a, 'b> {
@@ -219,6 +310,18 @@ fn args_parser<'a, 'b>() -> clap::App<'a, 'b> {
                 .help("this value passed to vagrant command")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("task_verb")
+                .help("run a named task defined in config file's [tasks] table")
+                .possible_value("run")
+                .index(2),
+        )
+        .arg(
+            clap::Arg::with_name("task_name")
+                .help("name of a task defined in config file's [tasks] table")
+                .value_name("TASK")
+                .index(3),
+        )
         .subcommand(
             clap::SubCommand::with_name("list").help("Show entries in vm_list of config file"),
         )
@@ -263,3 +366,82 @@ fn args_parser<'a, 'b>() -> clap::App<'a, 'b> {
                 ),
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_temp_config(contents: &str, suffix: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "vm_test_expand_alias_{}_{}.toml",
+            std::process::id(),
+            suffix
+        ));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn expand_alias_resolves_into_the_aliased_tokens() {
+        let path = write_temp_config(
+            r#"
+[alias]
+provision = "myvm -c 'up --provision'"
+"#,
+            "single",
+        );
+        let config = vm::Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let args = vec![OsString::from("vm"), OsString::from("provision")];
+        let expanded = expand_alias(args, &config).unwrap();
+
+        assert_eq!(
+            expanded,
+            vec![
+                OsString::from("vm"),
+                OsString::from("myvm"),
+                OsString::from("-c"),
+                OsString::from("up --provision"),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_alias_leaves_non_alias_names_untouched() {
+        let path = write_temp_config(
+            r#"
+[alias]
+provision = "myvm -c 'up --provision'"
+"#,
+            "passthrough",
+        );
+        let config = vm::Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let args = vec![OsString::from("vm"), OsString::from("myvm")];
+        let expanded = expand_alias(args.clone(), &config).unwrap();
+
+        assert_eq!(expanded, args);
+    }
+
+    #[test]
+    fn expand_alias_rejects_direct_recursion() {
+        let path = write_temp_config(
+            r#"
+[alias]
+loop_a = "loop_a"
+"#,
+            "recursion",
+        );
+        let config = vm::Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let args = vec![OsString::from("vm"), OsString::from("loop_a")];
+        let err = expand_alias(args, &config).unwrap_err();
+
+        assert!(err.to_string().contains("referenced recursively"));
+    }
+}